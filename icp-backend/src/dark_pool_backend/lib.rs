@@ -2,9 +2,40 @@ use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::time;
 use ic_cdk_macros::*;
 use serde::{Deserialize as SerdeDeserialize, Serialize as SerdeSerialize};
-use std::collections::HashMap;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
 use uuid::Uuid;
 
+// Domain tag mixed into every signed state-channel message so a signature
+// valid here can't be replayed against another protocol or message shape.
+const STATE_CHANNEL_DOMAIN: &[u8] = b"DARKPOOL_STATE_CHANNEL_V1";
+const CHALLENGE_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Length of one reveal-window epoch. The epoch is derived straight from
+// `time()` rather than kept as incrementing counter state, so the reveal
+// window actually elapses without needing a heartbeat or admin tick to
+// advance it.
+const EPOCH_DURATION_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+// On-disk record versions. Only order records are gated: `active_order_version`
+// writes CURRENT_ORDER_VERSION once `new_schema_enabled` is turned on via
+// `migrate_schema`, and version 1 until then, so an upgrade can still be
+// rolled back to a build that doesn't know about the new layout. Channel and
+// match records are written at CURRENT_CHANNEL_VERSION/CURRENT_MATCH_VERSION
+// directly.
+//
+// Migrating an order to version 2 re-derives its `remaining_amount` once as
+// `amount_in - filled_amount`, discarding any float drift accumulated across
+// `apply_fill`/`rollback_match` calls; later fills and rollbacks keep
+// updating it incrementally.
+const CURRENT_ORDER_VERSION: u32 = 2;
+const CURRENT_CHANNEL_VERSION: u32 = 1;
+const CURRENT_MATCH_VERSION: u32 = 1;
+
 // ============ TYPES ============
 
 #[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
@@ -22,6 +53,12 @@ pub struct Order {
     pub is_revealed: bool,
     pub is_executed: bool,
     pub is_cancelled: bool,
+    pub remaining_amount: String,
+    pub filled_amount: String,
+    pub chain_id: u64,
+    // On-disk schema version for this record; lets `migrate_schema` migrate
+    // records written under an older layout.
+    pub version: u32,
 }
 
 #[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
@@ -30,6 +67,28 @@ pub struct EncryptedOrder {
     pub commitment: String,
     pub timestamp: u64,
     pub nonce: u64,
+    pub trader: String,
+    pub token_in: String,
+    pub token_out: String,
+    pub amount_in: String,
+    pub amount_out: String,
+    pub is_buy: bool,
+    // Blinding factor the trader mixed into the commitment hash so the
+    // commitment can't be brute-forced from the small space of plausible
+    // order fields before reveal.
+    pub blinding: String,
+    // Must equal the canister's own chain id (see `get_chain_id`); ties the
+    // commitment to one deployment so it can't be replayed on another.
+    pub chain_id: u64,
+}
+
+// Bookkeeping kept alongside a commitment so reveal can enforce a bounded
+// window: a commitment older than `reveal_window_epochs` epochs is no longer
+// revealable and gets purged.
+#[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
+struct CommitmentRecord {
+    timestamp: u64,
+    epoch: u64,
 }
 
 #[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
@@ -41,6 +100,11 @@ pub struct StateChannel {
     pub last_update: u64,
     pub is_active: bool,
     pub emergency_withdraw_time: Option<u64>,
+    // The balance/nonce a participant proposed at emergency-withdrawal time;
+    // finalized once the challenge window closes without being superseded.
+    pub settlement_balance: Option<String>,
+    pub settlement_nonce: Option<u64>,
+    pub version: u32,
 }
 
 #[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
@@ -60,6 +124,11 @@ pub struct Balance {
     pub last_update: u64,
 }
 
+// Wraps `Vec<Balance>` so it can implement the foreign `Storable` trait --
+// Rust's orphan rule blocks implementing it directly on `Vec<Balance>`.
+#[derive(CandidType, Deserialize, Clone, Default, SerdeSerialize, SerdeDeserialize)]
+struct BalanceList(Vec<Balance>);
+
 #[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
 pub struct Match {
     pub id: String,
@@ -67,8 +136,36 @@ pub struct Match {
     pub sell_order: String,
     pub price: String,
     pub amount: String,
+    pub fee_amount: String,
     pub timestamp: u64,
     pub executed_at: u64,
+    pub version: u32,
+}
+
+// Matches are resting-book records; this is the narrower view of one, built
+// with `From<&Match>`, carrying only the fields settlement needs and none of
+// the orderbook bookkeeping. Nothing in this canister consumes it.
+#[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
+pub struct ExecutableMatch {
+    pub match_id: String,
+    pub buy_order: String,
+    pub sell_order: String,
+    pub price: String,
+    pub amount: String,
+    pub fee_amount: String,
+}
+
+impl From<&Match> for ExecutableMatch {
+    fn from(m: &Match) -> Self {
+        ExecutableMatch {
+            match_id: m.id.clone(),
+            buy_order: m.buy_order.clone(),
+            sell_order: m.sell_order.clone(),
+            price: m.price.clone(),
+            amount: m.amount.clone(),
+            fee_amount: m.fee_amount.clone(),
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
@@ -93,35 +190,156 @@ pub struct HealthStatus {
     pub epoch: u64,
     pub version: String,
     pub network: String,
+    pub storage_version: u32,
 }
 
+// The handful of scalar globals that don't warrant their own StableBTreeMap;
+// kept together in one stable `Cell` so they persist through the same
+// ic-stable-structures memory as everything else.
+#[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
+struct ScalarState {
+    is_paused: bool,
+    engine_public_key: String,
+    // Number of epochs a commitment stays revealable before it expires and
+    // must be re-committed; bounds the front-running window an observer gets
+    // on a committed-but-not-yet-revealed order.
+    reveal_window_epochs: u64,
+    // EIP-155-style network domain: mixed into every signed message so a
+    // signature collected on one deployment can't be replayed on another.
+    chain_id: u64,
+    // Active on-disk schema version. Stays at 1 (the legacy layout) until an
+    // admin opts in via `migrate_schema`, so a rollback to a build that only
+    // understands version 1 remains possible.
+    storage_version: u32,
+    new_schema_enabled: bool,
+    // Principal allowed to call settlement-only admin endpoints (e.g.
+    // `rollback_match`); set to the installer at `init` time.
+    admin: Option<Principal>,
+}
+
+impl Default for ScalarState {
+    fn default() -> Self {
+        ScalarState {
+            is_paused: false,
+            engine_public_key: String::new(),
+            reveal_window_epochs: 3,
+            chain_id: 0,
+            storage_version: 1,
+            new_schema_enabled: false,
+            admin: None,
+        }
+    }
+}
+
+// ic-stable-structures needs `Storable` on every value it stores; these
+// types are already CandidType, so round-trip them through candid rather
+// than hand-rolling a byte layout.
+macro_rules! impl_candid_storable {
+    ($ty:ty) => {
+        impl Storable for $ty {
+            fn to_bytes(&self) -> Cow<[u8]> {
+                Cow::Owned(candid::encode_one(self).expect("failed to encode for stable storage"))
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                candid::decode_one(&bytes).expect("failed to decode from stable storage")
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    };
+}
+
+impl_candid_storable!(Order);
+impl_candid_storable!(StateChannel);
+impl_candid_storable!(CommitmentRecord);
+impl_candid_storable!(TradingPair);
+impl_candid_storable!(BalanceList);
+impl_candid_storable!(Match);
+impl_candid_storable!(ScalarState);
+
 // ============ GLOBAL STATE ============
+//
+// Every store here is ic-stable-structures-backed (`StableBTreeMap`/`Cell`
+// over a `MemoryManager`), so it lives in stable memory from the moment it's
+// written and survives an upgrade without any pre_upgrade/post_upgrade
+// round-trip. `ic-stable-structures` hands out virtual memory through
+// `thread_local!`-wrapped `RefCell`s -- that's this crate's one required
+// access pattern, not a stylistic choice.
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static ORDERS: RefCell<StableBTreeMap<String, Order, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))))
+    );
+    static STATE_CHANNELS: RefCell<StableBTreeMap<String, StateChannel, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+    );
+    static COMMITMENTS: RefCell<StableBTreeMap<String, CommitmentRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+    );
+    static TRADING_PAIRS: RefCell<StableBTreeMap<String, TradingPair, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
+    );
+    // Keyed by user address; value wraps `Vec<Balance>` via `BalanceList`.
+    static USER_BALANCES: RefCell<StableBTreeMap<String, BalanceList, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
+    );
+    static MATCHES: RefCell<StableBTreeMap<String, Match, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))))
+    );
+    // Last nonce a trader has successfully revealed an order with; rejects a
+    // reused (trader, nonce) pair as a replay regardless of which commitment
+    // it arrives under.
+    static TRADER_NONCES: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))))
+    );
+    // Commitments that have already produced a live order, kept past reveal
+    // (the commitment itself is removed from COMMITMENTS) so the same
+    // commitment can't be admitted twice. `StableBTreeMap` has no native set
+    // type, so membership is just a key with an unused `u8` value.
+    static REVEALED_COMMITMENTS: RefCell<StableBTreeMap<String, u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))))
+    );
+    // The handful of scalar globals (pause flag, chain id, admin, ...); see
+    // `ScalarState`.
+    static SCALARS: RefCell<Cell<ScalarState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+            ScalarState::default(),
+        )
+    );
+}
+
+fn scalars() -> ScalarState {
+    SCALARS.with(|cell| cell.borrow().get().clone())
+}
+
+fn update_scalars(f: impl FnOnce(&mut ScalarState)) {
+    SCALARS.with(|cell| {
+        let mut state = cell.borrow().get().clone();
+        f(&mut state);
+        cell.borrow_mut().set(state);
+    });
+}
 
-static mut ORDERS: Option<HashMap<String, Order>> = None;
-static mut STATE_CHANNELS: Option<HashMap<String, StateChannel>> = None;
-static mut COMMITMENTS: Option<HashMap<String, u64>> = None;
-static mut TRADING_PAIRS: Option<HashMap<String, TradingPair>> = None;
-static mut USER_BALANCES: Option<HashMap<String, Vec<Balance>>> = None;
-static mut MATCHES: Option<HashMap<String, Match>> = None;
-static mut CURRENT_EPOCH: u64 = 0;
-static mut IS_PAUSED: bool = false;
-static mut ENGINE_PUBLIC_KEY: String = String::new();
+// Current reveal-window epoch, derived from wall-clock time rather than an
+// incrementing counter so it keeps advancing on its own.
+fn current_epoch() -> u64 {
+    time() / EPOCH_DURATION_NANOS
+}
 
 // ============ INITIALIZATION ============
 
 #[init]
 fn init() {
-    unsafe {
-        // Initialize global state
-        ORDERS = Some(HashMap::new());
-        STATE_CHANNELS = Some(HashMap::new());
-        COMMITMENTS = Some(HashMap::new());
-        MATCHES = Some(HashMap::new());
-        USER_BALANCES = Some(HashMap::new());
-        
-        // Initialize trading pairs
-        let mut trading_pairs = HashMap::new();
-        trading_pairs.insert(
+    TRADING_PAIRS.with(|pairs| {
+        let mut pairs = pairs.borrow_mut();
+        pairs.insert(
             "ETH/USDC".to_string(),
             TradingPair {
                 token_in: "0x0000000000000000000000000000000000000000".to_string(),
@@ -132,7 +350,7 @@ fn init() {
                 is_active: true,
             },
         );
-        trading_pairs.insert(
+        pairs.insert(
             "QNT/USDT".to_string(),
             TradingPair {
                 token_in: "0x4a220e6096b25eadb88358cb44068a3248254675".to_string(),
@@ -143,13 +361,54 @@ fn init() {
                 is_active: true,
             },
         );
-        TRADING_PAIRS = Some(trading_pairs);
-        
+    });
+
+    update_scalars(|s| {
         // Generate engine public key (in production, this would be a real key)
-        ENGINE_PUBLIC_KEY = "0x".to_string() + &hex::encode([0u8; 32]);
-        
-        CURRENT_EPOCH = 0;
-        IS_PAUSED = false;
+        s.engine_public_key = "0x".to_string() + &hex::encode([0u8; 32]);
+        s.is_paused = false;
+        s.chain_id = 0;
+        s.storage_version = 1;
+        s.new_schema_enabled = false;
+        s.admin = Some(ic_cdk::caller());
+    });
+}
+
+// Rejects the call unless it comes from the installer-assigned admin
+// principal; used to gate settlement-only endpoints like `rollback_match`.
+fn require_admin() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    match scalars().admin {
+        Some(admin) if admin == caller => Ok(()),
+        _ => Err("Caller is not authorized to perform this action".to_string()),
+    }
+}
+
+// ============ UPGRADE PERSISTENCE ============
+//
+// Every store above is ic-stable-structures-backed (`StableBTreeMap`/`Cell`
+// over a `MemoryManager`), so it already lives in stable memory and
+// survives an upgrade without any explicit save/restore step -- the
+// `thread_local!` blocks just re-attach to the same virtual memory regions
+// the next time they're accessed. `ic_cdk::storage::stable_save`/
+// `stable_restore` are intentionally unused here: that API and a
+// `MemoryManager` both claim raw stable memory from offset 0, so mixing them
+// would corrupt the manager's bookkeeping.
+
+#[pre_upgrade]
+fn pre_upgrade() {}
+
+#[post_upgrade]
+fn post_upgrade() {}
+
+// New records are written at version 1 until an admin opts in via
+// `migrate_schema`, so a rollback to a build that only understands version 1
+// remains possible.
+fn active_order_version() -> u32 {
+    if scalars().new_schema_enabled {
+        CURRENT_ORDER_VERSION
+    } else {
+        1
     }
 }
 
@@ -160,9 +419,10 @@ fn health() -> Result<HealthStatus, String> {
     Ok(HealthStatus {
         status: "healthy".to_string(),
         timestamp: time(),
-        epoch: unsafe { CURRENT_EPOCH },
+        epoch: current_epoch(),
         version: "1.0.0".to_string(),
         network: "ic".to_string(),
+        storage_version: scalars().storage_version,
     })
 }
 
@@ -173,116 +433,245 @@ fn get_version() -> Result<String, String> {
 
 #[query]
 fn get_system_status() -> Result<SystemStatus, String> {
-    unsafe {
-        let total_orders = ORDERS.as_ref().map_or(0, |orders| orders.len() as u64);
-        let active_channels = STATE_CHANNELS.as_ref().map_or(0, |channels| {
-            channels.values().filter(|c| c.is_active).count() as u64
-        });
-        
-        Ok(SystemStatus {
-            is_paused: IS_PAUSED,
-            total_orders,
-            active_channels,
-        })
-    }
+    let total_orders = ORDERS.with(|o| o.borrow().len());
+    let active_channels = STATE_CHANNELS.with(|channels| {
+        channels.borrow().iter().filter(|(_, c)| c.is_active).count() as u64
+    });
+    let state = scalars();
+
+    Ok(SystemStatus {
+        is_paused: state.is_paused,
+        total_orders,
+        active_channels,
+        storage_version: state.storage_version,
+    })
 }
 
 // ============ ORDER MANAGEMENT ============
 
 #[update]
 fn commit_order(commitment: String, timestamp: u64, trader: String) -> Result<String, String> {
-    if unsafe { IS_PAUSED } {
+    if scalars().is_paused {
         return Err("Trading is currently paused".to_string());
     }
-    
-    unsafe {
-        if COMMITMENTS.is_none() {
-            COMMITMENTS = Some(HashMap::new());
-        }
-        
-        let commitments = COMMITMENTS.as_mut().unwrap();
-        
-        // Check if commitment already exists
-        if commitments.contains_key(&commitment) {
-            return Err("Commitment already exists".to_string());
-        }
-        
-        // Store commitment
-        commitments.insert(commitment.clone(), timestamp);
-        
-        // Generate transaction ID
-        let tx_id = format!("commit-{}", Uuid::new_v4());
-        
-        ic_cdk::println!("Order committed: {} by {}", commitment, trader);
-        Ok(tx_id)
+
+    let already_exists = COMMITMENTS.with(|c| c.borrow().contains_key(&commitment));
+    if already_exists {
+        return Err("Commitment already exists".to_string());
     }
+
+    // Store commitment, stamped with the epoch it was made in so reveal
+    // can enforce a bounded reveal window.
+    COMMITMENTS.with(|c| {
+        c.borrow_mut().insert(
+            commitment.clone(),
+            CommitmentRecord {
+                timestamp,
+                epoch: current_epoch(),
+            },
+        )
+    });
+
+    // Generate transaction ID
+    let tx_id = format!("commit-{}", Uuid::new_v4());
+
+    ic_cdk::println!("Order committed: {} by {}", commitment, trader);
+    Ok(tx_id)
 }
 
-#[update]
-fn reveal_order(encrypted_order: EncryptedOrder) -> Result<bool, String> {
-    if unsafe { IS_PAUSED } {
-        return Err("Trading is currently paused".to_string());
+// Recomputes keccak256(chain_id || token_in || token_out || amount_in ||
+// amount_out || is_buy || nonce || trader || blinding) so reveal can check
+// it against the commitment the trader posted up front, binding the
+// revealed plaintext to that earlier commitment and to one network.
+fn compute_commitment_hash(
+    chain_id: u64,
+    token_in: &str,
+    token_out: &str,
+    amount_in: &str,
+    amount_out: &str,
+    is_buy: bool,
+    nonce: u64,
+    trader: &str,
+    blinding: &str,
+) -> String {
+    let mut hasher = Keccak256::new();
+    hasher.update(chain_id.to_be_bytes());
+    hasher.update(token_in.as_bytes());
+    hasher.update(token_out.as_bytes());
+    hasher.update(amount_in.as_bytes());
+    hasher.update(amount_out.as_bytes());
+    hasher.update([is_buy as u8]);
+    hasher.update(nonce.to_be_bytes());
+    hasher.update(trader.as_bytes());
+    hasher.update(blinding.as_bytes());
+    format!("0x{}", hex::encode(hasher.finalize()))
+}
+
+// Distinguishes the failure classes a reveal can hit so callers don't have
+// to pattern-match error strings.
+#[derive(CandidType, Deserialize, Clone, PartialEq, SerdeSerialize, SerdeDeserialize, Debug)]
+pub enum RevealError {
+    TradingPaused,
+    CommitmentNotFound,
+    RevealWindowExpired,
+    ChainIdMismatch,
+    InvalidCommitment,
+    DuplicateOrder,
+    NonceReplay,
+    InactiveTradingPair,
+    InvalidAmount,
+    AmountOutOfBounds,
+}
+
+// Validates a revealed order before it's admitted to the book: the pair it
+// trades must exist and be active, its amounts must parse as positive and
+// sit within the pair's size bounds, and it must not be a duplicate of an
+// order that's already live.
+fn sanitize_order(encrypted_order: &EncryptedOrder) -> Result<(), RevealError> {
+    let already_revealed =
+        REVEALED_COMMITMENTS.with(|r| r.borrow().contains_key(&encrypted_order.commitment));
+    if already_revealed {
+        return Err(RevealError::DuplicateOrder);
     }
-    
-    unsafe {
-        // Verify commitment exists
-        if let Some(commitments) = &COMMITMENTS {
-            if !commitments.contains_key(&encrypted_order.commitment) {
-                return Err("Commitment not found".to_string());
-            }
-        }
-        
-        // In production, this would decrypt and verify the order
-        // For now, we'll create a mock order
-        let order_id = format!("order-{}", Uuid::new_v4());
-        
-        if ORDERS.is_none() {
-            ORDERS = Some(HashMap::new());
+
+    let last_nonce = TRADER_NONCES.with(|n| n.borrow().get(&encrypted_order.trader));
+    if let Some(last_nonce) = last_nonce {
+        if encrypted_order.nonce <= last_nonce {
+            return Err(RevealError::NonceReplay);
         }
-        
-        let orders = ORDERS.as_mut().unwrap();
-        
-        // Mock order creation (in production, decrypt encrypted_order.encrypted_data)
-        let order = Order {
-            id: order_id.clone(),
-            trader: "mock-trader".to_string(), // Would be extracted from decrypted data
-            token_in: "ETH".to_string(),
-            token_out: "USDC".to_string(),
-            amount_in: "1.0".to_string(),
-            amount_out: "2000".to_string(),
-            is_buy: true,
-            nonce: encrypted_order.nonce,
-            timestamp: encrypted_order.timestamp,
-            commitment: encrypted_order.commitment.clone(),
-            is_revealed: true,
-            is_executed: false,
-            is_cancelled: false,
-        };
-        
-        orders.insert(order_id, order);
-        
-        // Remove commitment after reveal
-        if let Some(commitments) = COMMITMENTS.as_mut() {
-            commitments.remove(&encrypted_order.commitment);
+    }
+
+    let pair = find_trading_pair(&encrypted_order.token_in, &encrypted_order.token_out)
+        .ok_or(RevealError::InactiveTradingPair)?;
+    if !pair.is_active {
+        return Err(RevealError::InactiveTradingPair);
+    }
+
+    let amount_in: f64 = encrypted_order
+        .amount_in
+        .parse()
+        .map_err(|_| RevealError::InvalidAmount)?;
+    let amount_out: f64 = encrypted_order
+        .amount_out
+        .parse()
+        .map_err(|_| RevealError::InvalidAmount)?;
+    if !amount_in.is_finite() || !amount_out.is_finite() || amount_in <= 0.0 || amount_out <= 0.0 {
+        return Err(RevealError::InvalidAmount);
+    }
+
+    let min_size: f64 = pair.min_order_size.parse().unwrap_or(0.0);
+    let max_size: f64 = pair.max_order_size.parse().unwrap_or(f64::MAX);
+    if amount_in < min_size || amount_in > max_size {
+        return Err(RevealError::AmountOutOfBounds);
+    }
+
+    Ok(())
+}
+
+fn purge_expired_commitments(current_epoch: u64) {
+    let reveal_window = scalars().reveal_window_epochs;
+    let expired: Vec<String> = COMMITMENTS.with(|c| {
+        c.borrow()
+            .iter()
+            .filter(|(_, record)| current_epoch.saturating_sub(record.epoch) > reveal_window)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    COMMITMENTS.with(|c| {
+        let mut commitments = c.borrow_mut();
+        for key in expired {
+            commitments.remove(&key);
         }
-        
-        ic_cdk::println!("Order revealed: {}", encrypted_order.commitment);
-        Ok(true)
+    });
+}
+
+#[update]
+fn reveal_order(encrypted_order: EncryptedOrder) -> Result<String, RevealError> {
+    if scalars().is_paused {
+        return Err(RevealError::TradingPaused);
+    }
+
+    let record = COMMITMENTS
+        .with(|c| c.borrow().get(&encrypted_order.commitment))
+        .ok_or(RevealError::CommitmentNotFound)?;
+
+    let reveal_window = scalars().reveal_window_epochs;
+    if current_epoch().saturating_sub(record.epoch) > reveal_window {
+        COMMITMENTS.with(|c| c.borrow_mut().remove(&encrypted_order.commitment));
+        return Err(RevealError::RevealWindowExpired);
     }
+
+    if encrypted_order.chain_id != scalars().chain_id {
+        return Err(RevealError::ChainIdMismatch);
+    }
+
+    let expected_commitment = compute_commitment_hash(
+        encrypted_order.chain_id,
+        &encrypted_order.token_in,
+        &encrypted_order.token_out,
+        &encrypted_order.amount_in,
+        &encrypted_order.amount_out,
+        encrypted_order.is_buy,
+        encrypted_order.nonce,
+        &encrypted_order.trader,
+        &encrypted_order.blinding,
+    );
+    if expected_commitment != encrypted_order.commitment {
+        return Err(RevealError::InvalidCommitment);
+    }
+
+    sanitize_order(&encrypted_order)?;
+
+    purge_expired_commitments(current_epoch());
+
+    let order_id = format!("order-{}", Uuid::new_v4());
+
+    let order = Order {
+        id: order_id.clone(),
+        trader: encrypted_order.trader.clone(),
+        token_in: encrypted_order.token_in.clone(),
+        token_out: encrypted_order.token_out.clone(),
+        amount_in: encrypted_order.amount_in.clone(),
+        amount_out: encrypted_order.amount_out.clone(),
+        is_buy: encrypted_order.is_buy,
+        nonce: encrypted_order.nonce,
+        timestamp: encrypted_order.timestamp,
+        commitment: encrypted_order.commitment.clone(),
+        is_revealed: true,
+        is_executed: false,
+        is_cancelled: false,
+        remaining_amount: encrypted_order.amount_in.clone(),
+        filled_amount: "0".to_string(),
+        chain_id: encrypted_order.chain_id,
+        version: active_order_version(),
+    };
+
+    ORDERS.with(|o| o.borrow_mut().insert(order_id.clone(), order));
+
+    // Remove commitment after reveal
+    COMMITMENTS.with(|c| c.borrow_mut().remove(&encrypted_order.commitment));
+    TRADER_NONCES.with(|n| {
+        n.borrow_mut()
+            .insert(encrypted_order.trader.clone(), encrypted_order.nonce)
+    });
+    REVEALED_COMMITMENTS
+        .with(|r| r.borrow_mut().insert(encrypted_order.commitment.clone(), 1u8));
+
+    run_matching_engine(&order_id);
+
+    ic_cdk::println!("Order revealed: {}", encrypted_order.commitment);
+    Ok(order_id)
 }
 
 #[query]
 fn get_order_book(trading_pair: String) -> Result<OrderBook, String> {
-    unsafe {
-        if ORDERS.is_none() {
-            return Ok(OrderBook { buys: vec![], sells: vec![] });
-        }
-        
-        let orders = ORDERS.as_ref().unwrap();
-        let mut buys = vec![];
-        let mut sells = vec![];
-        
-        for order in orders.values() {
+    let _ = trading_pair;
+    let mut buys = vec![];
+    let mut sells = vec![];
+
+    ORDERS.with(|orders| {
+        for (_, order) in orders.borrow().iter() {
             if !order.is_cancelled && !order.is_executed {
                 if order.is_buy {
                     buys.push(order.clone());
@@ -291,298 +680,727 @@ fn get_order_book(trading_pair: String) -> Result<OrderBook, String> {
                 }
             }
         }
-        
-        // Sort by price and time
-        buys.sort_by(|a, b| {
-            let price_a: f64 = a.amount_out.parse().unwrap_or(0.0);
-            let price_b: f64 = b.amount_out.parse().unwrap_or(0.0);
-            price_b.partial_cmp(&price_a).unwrap_or(std::cmp::Ordering::Equal)
-        });
-        
-        sells.sort_by(|a, b| {
-            let price_a: f64 = a.amount_out.parse().unwrap_or(0.0);
-            let price_b: f64 = b.amount_out.parse().unwrap_or(0.0);
-            price_a.partial_cmp(&price_b).unwrap_or(std::cmp::Ordering::Equal)
-        });
-        
-        Ok(OrderBook { buys, sells })
-    }
+    });
+
+    // Sort by amount_out: buys highest first, sells lowest first; ties are
+    // not ordered by time.
+    buys.sort_by(|a, b| {
+        let price_a: f64 = a.amount_out.parse().unwrap_or(0.0);
+        let price_b: f64 = b.amount_out.parse().unwrap_or(0.0);
+        price_b.partial_cmp(&price_a).unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    sells.sort_by(|a, b| {
+        let price_a: f64 = a.amount_out.parse().unwrap_or(0.0);
+        let price_b: f64 = b.amount_out.parse().unwrap_or(0.0);
+        price_a.partial_cmp(&price_b).unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    Ok(OrderBook { buys, sells })
 }
 
 #[query]
 fn get_order(order_id: String) -> Result<Option<Order>, String> {
-    unsafe {
-        if let Some(orders) = &ORDERS {
-            Ok(orders.get(&order_id).cloned())
-        } else {
-            Ok(None)
-        }
-    }
+    Ok(ORDERS.with(|o| o.borrow().get(&order_id)))
 }
 
 #[update]
 fn cancel_order(order_id: String, trader: String) -> Result<bool, String> {
-    unsafe {
-        if let Some(orders) = ORDERS.as_mut() {
-            if let Some(order) = orders.get_mut(&order_id) {
-                if order.trader == trader && !order.is_executed {
-                    order.is_cancelled = true;
-                    ic_cdk::println!("Order cancelled: {} by {}", order_id, trader);
-                    return Ok(true);
-                }
+    let cancelled = ORDERS.with(|o| {
+        let mut orders = o.borrow_mut();
+        if let Some(mut order) = orders.get(&order_id) {
+            if order.trader == trader && !order.is_executed {
+                order.is_cancelled = true;
+                orders.insert(order_id.clone(), order);
+                return true;
             }
         }
+        false
+    });
+
+    if cancelled {
+        ic_cdk::println!("Order cancelled: {} by {}", order_id, trader);
+        Ok(true)
+    } else {
         Err("Order not found or cannot be cancelled".to_string())
     }
 }
 
+// ============ MATCHING ENGINE ============
+//
+// The order book (ORDERS) only tracks resting liquidity. Matching walks it
+// to produce Match records; ExecutableMatch exposes a Match without the
+// book's bookkeeping, and a failed settlement is undone with
+// `rollback_match`, which restores both orders from the Match record alone.
+
+// A buy and its matching sell give/receive the pair's two tokens in opposite
+// directions (a buy gives the pair's `token_out` to receive `token_in`, a
+// sell does the reverse), so `amount_out / amount_in` lands in reciprocal
+// units depending on which side an order is on. Normalize both to the same
+// quote/base unit -- price expressed as "how much of the pair's token_out an
+// order pays or charges per unit of its token_in" -- before comparing.
+fn order_price(order: &Order, pair: &TradingPair) -> f64 {
+    let amount_in: f64 = order.amount_in.parse().unwrap_or(0.0);
+    let amount_out: f64 = order.amount_out.parse().unwrap_or(0.0);
+    if order.token_in == pair.token_in {
+        if amount_in <= 0.0 {
+            0.0
+        } else {
+            amount_out / amount_in
+        }
+    } else {
+        if amount_out <= 0.0 {
+            0.0
+        } else {
+            amount_in / amount_out
+        }
+    }
+}
+
+fn remaining_amount_f64(order: &Order) -> f64 {
+    order.remaining_amount.parse().unwrap_or(0.0)
+}
+
+fn find_trading_pair(token_in: &str, token_out: &str) -> Option<TradingPair> {
+    TRADING_PAIRS.with(|pairs| {
+        pairs
+            .borrow()
+            .iter()
+            .map(|(_, pair)| pair)
+            .find(|pair| {
+                (pair.token_in == token_in && pair.token_out == token_out)
+                    || (pair.token_in == token_out && pair.token_out == token_in)
+            })
+    })
+}
+
+fn apply_fill(order_id: &str, fill_amount: f64) {
+    ORDERS.with(|o| {
+        let mut orders = o.borrow_mut();
+        if let Some(mut order) = orders.get(order_id) {
+            let remaining = remaining_amount_f64(&order) - fill_amount;
+            let filled: f64 = order.filled_amount.parse().unwrap_or(0.0) + fill_amount;
+            order.remaining_amount = remaining.max(0.0).to_string();
+            order.filled_amount = filled.to_string();
+            if remaining <= 0.0 {
+                order.is_executed = true;
+            }
+            orders.insert(order_id.to_string(), order);
+        }
+    });
+}
+
+// Runs after an order enters the book: crosses it against resting liquidity
+// on the opposite side, filling `min(remaining_buy, remaining_sell)` per
+// resting order until the incoming order is exhausted or no more orders
+// cross its limit price.
+fn run_matching_engine(order_id: &str) {
+    let incoming = match ORDERS.with(|o| o.borrow().get(order_id)) {
+        Some(order) => order,
+        None => return,
+    };
+
+    if incoming.is_cancelled || incoming.is_executed || remaining_amount_f64(&incoming) <= 0.0 {
+        return;
+    }
+
+    let pair = match find_trading_pair(&incoming.token_in, &incoming.token_out) {
+        Some(pair) => pair,
+        None => return,
+    };
+
+    let incoming_rate = order_price(&incoming, &pair);
+
+    // Resting orders on the opposite side, sorted price-then-time:
+    // ascending for a buy crossing the book, descending for a sell.
+    let mut candidate_ids: Vec<String> = ORDERS.with(|o| {
+        o.borrow()
+            .iter()
+            .filter(|(_, candidate)| {
+                candidate.id != incoming.id
+                    && candidate.is_buy != incoming.is_buy
+                    && !candidate.is_cancelled
+                    && !candidate.is_executed
+                    && remaining_amount_f64(candidate) > 0.0
+                    && candidate.token_in == incoming.token_out
+                    && candidate.token_out == incoming.token_in
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    candidate_ids.sort_by(|a, b| {
+        let order_a = ORDERS.with(|o| o.borrow().get(a)).unwrap();
+        let order_b = ORDERS.with(|o| o.borrow().get(b)).unwrap();
+        let rate_a = order_price(&order_a, &pair);
+        let rate_b = order_price(&order_b, &pair);
+        let rate_order = if incoming.is_buy {
+            rate_a.partial_cmp(&rate_b)
+        } else {
+            rate_b.partial_cmp(&rate_a)
+        };
+        rate_order
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(order_a.timestamp.cmp(&order_b.timestamp))
+    });
+
+    let min_size: f64 = pair.min_order_size.parse().unwrap_or(0.0);
+    let max_size: f64 = pair.max_order_size.parse().unwrap_or(f64::MAX);
+    let mut remaining_incoming = remaining_amount_f64(&incoming);
+
+    for candidate_id in candidate_ids {
+        if remaining_incoming <= 0.0 {
+            break;
+        }
+
+        let candidate = match ORDERS.with(|o| o.borrow().get(&candidate_id)) {
+            Some(candidate) => candidate,
+            None => continue,
+        };
+        let candidate_rate = order_price(&candidate, &pair);
+
+        let crosses = if incoming.is_buy {
+            candidate_rate <= incoming_rate
+        } else {
+            candidate_rate >= incoming_rate
+        };
+        if !crosses {
+            break;
+        }
+
+        let remaining_candidate = remaining_amount_f64(&candidate);
+        let fill_amount = remaining_incoming.min(remaining_candidate);
+        if fill_amount < min_size || fill_amount > max_size {
+            continue;
+        }
+
+        let fee_amount = fill_amount * (pair.trading_fee as f64) / 10_000.0;
+        let (buy_order, sell_order) = if incoming.is_buy {
+            (incoming.id.clone(), candidate.id.clone())
+        } else {
+            (candidate.id.clone(), incoming.id.clone())
+        };
+
+        let match_id = format!("match-{}", Uuid::new_v4());
+        let now = time();
+        let new_match = Match {
+            id: match_id.clone(),
+            buy_order,
+            sell_order,
+            price: candidate_rate.to_string(),
+            amount: fill_amount.to_string(),
+            fee_amount: fee_amount.to_string(),
+            timestamp: now,
+            executed_at: now,
+            version: CURRENT_MATCH_VERSION,
+        };
+
+        apply_fill(&incoming.id, fill_amount);
+        apply_fill(&candidate.id, fill_amount);
+
+        MATCHES.with(|m| m.borrow_mut().insert(match_id, new_match));
+
+        remaining_incoming -= fill_amount;
+    }
+}
+
+// Undoes a match whose downstream settlement failed or never completed:
+// restores both orders' remaining/filled amounts and drops the record so it
+// can't be executed again. Admin-only: this un-executes an otherwise
+// completed trade, so it must not be callable by arbitrary principals.
+#[update]
+fn rollback_match(match_id: String) -> Result<bool, String> {
+    require_admin()?;
+
+    let matched = MATCHES
+        .with(|m| m.borrow_mut().remove(&match_id))
+        .ok_or("Match not found")?;
+
+    let amount: f64 = matched.amount.parse().unwrap_or(0.0);
+    for order_id in [&matched.buy_order, &matched.sell_order] {
+        ORDERS.with(|o| {
+            let mut orders = o.borrow_mut();
+            if let Some(mut order) = orders.get(order_id) {
+                let remaining: f64 = remaining_amount_f64(&order) + amount;
+                let filled: f64 = (order.filled_amount.parse().unwrap_or(0.0) - amount).max(0.0);
+                order.remaining_amount = remaining.to_string();
+                order.filled_amount = filled.to_string();
+                order.is_executed = false;
+                orders.insert(order_id.clone(), order);
+            }
+        });
+    }
+
+    ic_cdk::println!("Match rolled back: {}", match_id);
+    Ok(true)
+}
+
 // ============ STATE CHANNELS ============
 
+// keccak256(domain || chain_id || channel_id || new_balance || nonce),
+// binding a signed state update to one channel, one network, and one nonce.
+fn state_update_hash(chain_id: u64, channel_id: &str, balance: &str, nonce: u64) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(STATE_CHANNEL_DOMAIN);
+    hasher.update(chain_id.to_be_bytes());
+    hasher.update(channel_id.as_bytes());
+    hasher.update(balance.as_bytes());
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().into()
+}
+
+// Recovers the Ethereum-style address (0x + last 20 bytes of
+// keccak256(uncompressed pubkey)) that produced `signature` over
+// `message_hash`. `signature` is a 65-byte r||s||v hex string, optionally
+// 0x-prefixed.
+fn recover_eth_address(message_hash: &[u8; 32], signature: &str) -> Result<String, String> {
+    let sig_hex = signature.trim_start_matches("0x");
+    let sig_bytes = hex::decode(sig_hex).map_err(|_| "Signature is not valid hex".to_string())?;
+    if sig_bytes.len() != 65 {
+        return Err("Signature must be 65 bytes (r || s || v)".to_string());
+    }
+
+    let recovery_id = RecoveryId::from_byte(sig_bytes[64] % 27)
+        .ok_or_else(|| "Invalid signature recovery id".to_string())?;
+    let signature =
+        Signature::from_slice(&sig_bytes[..64]).map_err(|_| "Invalid signature".to_string())?;
+
+    let verifying_key = VerifyingKey::recover_from_prehash(message_hash, &signature, recovery_id)
+        .map_err(|_| "Failed to recover signer from signature".to_string())?;
+
+    let encoded_point = verifying_key.to_encoded_point(false);
+    let pubkey_bytes = encoded_point.as_bytes();
+    // Skip the 0x04 uncompressed-point prefix before hashing, per the
+    // Ethereum address derivation scheme.
+    let hash = Keccak256::digest(&pubkey_bytes[1..]);
+    Ok(format!("0x{}", hex::encode(&hash[12..])))
+}
+
+fn is_channel_participant(channel: &StateChannel, address: &str) -> bool {
+    channel
+        .participants
+        .iter()
+        .any(|p| p.eq_ignore_ascii_case(address))
+}
+
 #[update]
 fn open_state_channel(participant: String, initial_balance: String, collateral: String) -> Result<StateChannel, String> {
-    unsafe {
-        if STATE_CHANNELS.is_none() {
-            STATE_CHANNELS = Some(HashMap::new());
+    let _ = collateral;
+    let channel_id = format!("channel-{}", Uuid::new_v4());
+
+    let channel = StateChannel {
+        id: channel_id.clone(),
+        participants: vec![participant.clone()],
+        balance: initial_balance,
+        nonce: 0,
+        last_update: time(),
+        is_active: true,
+        emergency_withdraw_time: None,
+        settlement_balance: None,
+        settlement_nonce: None,
+        version: CURRENT_CHANNEL_VERSION,
+    };
+
+    STATE_CHANNELS.with(|channels| channels.borrow_mut().insert(channel_id.clone(), channel.clone()));
+
+    ic_cdk::println!("State channel opened: {} for {}", channel_id, participant);
+    Ok(channel)
+}
+
+// Adds a second participant to a channel opened by `open_state_channel`.
+// Without this, `participants` can never hold more than the opener, so the
+// counterparty-supersedes-withdrawal path `challenge_withdrawal` implements
+// has no second signer to ever come from.
+#[update]
+fn join_state_channel(channel_id: String, participant: String) -> Result<StateChannel, String> {
+    STATE_CHANNELS.with(|channels| {
+        let mut channels = channels.borrow_mut();
+        let mut channel = channels.get(&channel_id).ok_or("State channel not found")?;
+
+        if !channel.is_active {
+            return Err("Channel is not active".to_string());
         }
-        
-        let channels = STATE_CHANNELS.as_mut().unwrap();
-        let channel_id = format!("channel-{}", Uuid::new_v4());
-        
-        let channel = StateChannel {
-            id: channel_id.clone(),
-            participants: vec![participant.clone()],
-            balance: initial_balance.clone(),
-            nonce: 0,
-            last_update: time(),
-            is_active: true,
-            emergency_withdraw_time: None,
-        };
-        
+        if channel.participants.len() >= 2 {
+            return Err("Channel already has two participants".to_string());
+        }
+        if is_channel_participant(&channel, &participant) {
+            return Err("Address is already a channel participant".to_string());
+        }
+
+        channel.participants.push(participant.clone());
+        channel.last_update = time();
         channels.insert(channel_id.clone(), channel.clone());
-        
-        ic_cdk::println!("State channel opened: {} for {}", channel_id, participant);
+
+        ic_cdk::println!("State channel joined: {} by {}", channel_id, participant);
         Ok(channel)
-    }
+    })
 }
 
 #[update]
-fn update_state_channel(channel_id: String, new_balance: String, signature: String) -> Result<bool, String> {
-    unsafe {
-        if let Some(channels) = STATE_CHANNELS.as_mut() {
-            if let Some(channel) = channels.get_mut(&channel_id) {
-                // In production, verify signature here
-                channel.balance = new_balance;
-                channel.nonce += 1;
-                channel.last_update = time();
-                
-                ic_cdk::println!("State channel updated: {}", channel_id);
-                return Ok(true);
-            }
+fn update_state_channel(
+    channel_id: String,
+    new_balance: String,
+    nonce: u64,
+    signature: String,
+) -> Result<bool, String> {
+    let chain_id = scalars().chain_id;
+    STATE_CHANNELS.with(|channels| {
+        let mut channels = channels.borrow_mut();
+        let mut channel = channels.get(&channel_id).ok_or("State channel not found")?;
+
+        if nonce <= channel.nonce {
+            return Err("Nonce must be strictly increasing".to_string());
         }
-        Err("State channel not found".to_string())
-    }
+
+        let message_hash = state_update_hash(chain_id, &channel_id, &new_balance, nonce);
+        let signer = recover_eth_address(&message_hash, &signature)?;
+        if !is_channel_participant(&channel, &signer) {
+            return Err("Signature is not from a channel participant".to_string());
+        }
+
+        channel.balance = new_balance;
+        channel.nonce = nonce;
+        channel.last_update = time();
+        channels.insert(channel_id.clone(), channel);
+
+        ic_cdk::println!("State channel updated: {}", channel_id);
+        Ok(true)
+    })
 }
 
 #[query]
 fn get_state_channel(channel_id: String) -> Result<Option<StateChannel>, String> {
-    unsafe {
-        if let Some(channels) = &STATE_CHANNELS {
-            Ok(channels.get(&channel_id).cloned())
-        } else {
-            Ok(None)
-        }
-    }
+    Ok(STATE_CHANNELS.with(|channels| channels.borrow().get(&channel_id)))
 }
 
 #[query]
 fn get_user_state_channels(user_address: String) -> Result<Vec<StateChannel>, String> {
-    unsafe {
-        if let Some(channels) = &STATE_CHANNELS {
-            let user_channels: Vec<StateChannel> = channels
-                .values()
-                .filter(|channel| channel.participants.contains(&user_address))
-                .cloned()
-                .collect();
-            Ok(user_channels)
-        } else {
-            Ok(vec![])
+    Ok(STATE_CHANNELS.with(|channels| {
+        channels
+            .borrow()
+            .iter()
+            .map(|(_, channel)| channel)
+            .filter(|channel| channel.participants.contains(&user_address))
+            .collect()
+    }))
+}
+
+// Initiates an emergency withdrawal from the latest co-signed state. Opens a
+// 24h challenge window during which a counterparty can supersede this
+// balance/nonce with a higher-nonce signed state via `challenge_withdrawal`;
+// after the window closes, `finalize_withdrawal` settles at whichever state
+// won.
+#[update]
+fn emergency_withdrawal(
+    channel_id: String,
+    balance: String,
+    nonce: u64,
+    signature: String,
+) -> Result<bool, String> {
+    let chain_id = scalars().chain_id;
+    STATE_CHANNELS.with(|channels| {
+        let mut channels = channels.borrow_mut();
+        let mut channel = channels.get(&channel_id).ok_or("State channel not found")?;
+
+        if !channel.is_active {
+            return Err("Emergency withdrawal already in progress".to_string());
         }
-    }
+        if nonce < channel.nonce {
+            return Err("Stale state: nonce behind the last confirmed update".to_string());
+        }
+
+        let message_hash = state_update_hash(chain_id, &channel_id, &balance, nonce);
+        let signer = recover_eth_address(&message_hash, &signature)?;
+        if !is_channel_participant(&channel, &signer) {
+            return Err("Signature is not from a channel participant".to_string());
+        }
+
+        channel.settlement_balance = Some(balance);
+        channel.settlement_nonce = Some(nonce);
+        channel.is_active = false;
+        channel.emergency_withdraw_time = Some(time() + CHALLENGE_WINDOW_NANOS);
+        channels.insert(channel_id.clone(), channel);
+
+        ic_cdk::println!("Emergency withdrawal initiated for channel: {}", channel_id);
+        Ok(true)
+    })
 }
 
+// Lets a counterparty post a higher-nonce co-signed state during the
+// challenge window, superseding the pending settlement and restarting the
+// window.
 #[update]
-fn emergency_withdrawal(channel_id: String) -> Result<bool, String> {
-    unsafe {
-        if let Some(channels) = STATE_CHANNELS.as_mut() {
-            if let Some(channel) = channels.get_mut(&channel_id) {
-                if channel.is_active {
-                    channel.emergency_withdraw_time = Some(time() + 86400); // 24 hours
-                    channel.is_active = false;
-                    
-                    ic_cdk::println!("Emergency withdrawal initiated for channel: {}", channel_id);
-                    return Ok(true);
-                }
-            }
+fn challenge_withdrawal(
+    channel_id: String,
+    balance: String,
+    nonce: u64,
+    signature: String,
+) -> Result<bool, String> {
+    let chain_id = scalars().chain_id;
+    STATE_CHANNELS.with(|channels| {
+        let mut channels = channels.borrow_mut();
+        let mut channel = channels.get(&channel_id).ok_or("State channel not found")?;
+
+        let deadline = channel
+            .emergency_withdraw_time
+            .ok_or("No emergency withdrawal is in progress")?;
+        if time() >= deadline {
+            return Err("Challenge window has closed".to_string());
         }
-        Err("State channel not found or not active".to_string())
-    }
+        if nonce <= channel.settlement_nonce.unwrap_or(0) {
+            return Err("Challenge must supersede with a higher nonce".to_string());
+        }
+
+        let message_hash = state_update_hash(chain_id, &channel_id, &balance, nonce);
+        let signer = recover_eth_address(&message_hash, &signature)?;
+        if !is_channel_participant(&channel, &signer) {
+            return Err("Signature is not from a channel participant".to_string());
+        }
+
+        channel.settlement_balance = Some(balance);
+        channel.settlement_nonce = Some(nonce);
+        channel.emergency_withdraw_time = Some(time() + CHALLENGE_WINDOW_NANOS);
+        channels.insert(channel_id.clone(), channel);
+
+        ic_cdk::println!("Emergency withdrawal challenged for channel: {}", channel_id);
+        Ok(true)
+    })
+}
+
+// Settles the channel at the winning state once the challenge window has
+// elapsed with no further challenge.
+#[update]
+fn finalize_withdrawal(channel_id: String) -> Result<StateChannel, String> {
+    STATE_CHANNELS.with(|channels| {
+        let mut channels = channels.borrow_mut();
+        let mut channel = channels.get(&channel_id).ok_or("State channel not found")?;
+
+        let deadline = channel
+            .emergency_withdraw_time
+            .ok_or("No emergency withdrawal is in progress")?;
+        if time() < deadline {
+            return Err("Challenge window is still open".to_string());
+        }
+
+        let settled_balance = channel
+            .settlement_balance
+            .clone()
+            .ok_or("No settled balance recorded")?;
+        channel.balance = settled_balance;
+        channel.nonce = channel.settlement_nonce.unwrap_or(channel.nonce);
+        channel.emergency_withdraw_time = None;
+        channels.insert(channel_id.clone(), channel.clone());
+
+        ic_cdk::println!("Emergency withdrawal finalized for channel: {}", channel_id);
+        Ok(channel)
+    })
 }
 
 // ============ TRADING PAIRS AND BALANCES ============
 
 #[query]
 fn get_trading_pairs() -> Result<Vec<TradingPairRecord>, String> {
-    unsafe {
-        if let Some(pairs) = &TRADING_PAIRS {
-            let result: Vec<TradingPairRecord> = pairs
-                .iter()
-                .map(|(pair, config)| TradingPairRecord {
-                    pair: pair.clone(),
-                    config: config.clone(),
-                })
-                .collect();
-            Ok(result)
-        } else {
-            Ok(vec![])
-        }
-    }
+    Ok(TRADING_PAIRS.with(|pairs| {
+        pairs
+            .borrow()
+            .iter()
+            .map(|(pair, config)| TradingPairRecord { pair, config })
+            .collect()
+    }))
 }
 
 #[query]
 fn get_user_balances(user_address: String) -> Result<Vec<Balance>, String> {
-    unsafe {
-        if let Some(balances) = &USER_BALANCES {
-            Ok(balances.get(&user_address).cloned().unwrap_or(vec![]))
-        } else {
-            Ok(vec![])
-        }
-    }
+    Ok(USER_BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .get(&user_address)
+            .map(|list| list.0)
+            .unwrap_or_default()
+    }))
 }
 
 #[update]
 fn update_balance(user_address: String, token: String, amount: String) -> Result<bool, String> {
-    unsafe {
-        if USER_BALANCES.is_none() {
-            USER_BALANCES = Some(HashMap::new());
-        }
-        
-        let balances = USER_BALANCES.as_mut().unwrap();
-        let user_balances = balances.entry(user_address).or_insert_with(Vec::new);
-        
+    USER_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let mut user_balances = balances.get(&user_address).unwrap_or_default();
+
         // Update existing balance or add new one
-        if let Some(balance) = user_balances.iter_mut().find(|b| b.token == token) {
+        if let Some(balance) = user_balances.0.iter_mut().find(|b| b.token == token) {
             balance.amount = amount;
             balance.last_update = time();
         } else {
-            user_balances.push(Balance {
+            user_balances.0.push(Balance {
                 token,
                 amount,
                 last_update: time(),
             });
         }
-        
-        Ok(true)
-    }
+
+        balances.insert(user_address, user_balances);
+    });
+
+    Ok(true)
 }
 
 // ============ STATISTICS AND MONITORING ============
 
 #[query]
 fn get_network_stats() -> Result<NetworkStats, String> {
-    unsafe {
-        let total_orders = ORDERS.as_ref().map_or(0, |orders| orders.len() as u64);
-        let total_matches = MATCHES.as_ref().map_or(0, |matches| matches.len() as u64);
-        let active_channels = STATE_CHANNELS.as_ref().map_or(0, |channels| {
-            channels.values().filter(|c| c.is_active).count() as u64
-        });
-        
-        Ok(NetworkStats {
-            total_orders,
-            total_matches,
-            total_volume: "1250000.0".to_string(), // Mock data
-            active_channels,
-            average_price: "2000.0".to_string(), // Mock data
-        })
-    }
+    let total_orders = ORDERS.with(|o| o.borrow().len());
+    let total_matches = MATCHES.with(|m| m.borrow().len());
+    let active_channels = STATE_CHANNELS.with(|channels| {
+        channels.borrow().iter().filter(|(_, c)| c.is_active).count() as u64
+    });
+
+    Ok(NetworkStats {
+        total_orders,
+        total_matches,
+        total_volume: "1250000.0".to_string(), // Mock data
+        active_channels,
+        average_price: "2000.0".to_string(), // Mock data
+    })
 }
 
 #[query]
 fn get_recent_matches(limit: u64) -> Result<Vec<Match>, String> {
-    unsafe {
-        if let Some(matches) = &MATCHES {
-            let mut recent_matches: Vec<Match> = matches.values().cloned().collect();
-            recent_matches.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-            recent_matches.truncate(limit as usize);
-            Ok(recent_matches)
-        } else {
-            Ok(vec![])
-        }
-    }
+    let mut recent_matches: Vec<Match> =
+        MATCHES.with(|m| m.borrow().iter().map(|(_, v)| v).collect());
+    recent_matches.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    recent_matches.truncate(limit as usize);
+    Ok(recent_matches)
 }
 
 #[query]
 fn get_epoch_info() -> Result<EpochInfo, String> {
-    unsafe {
-        Ok(EpochInfo {
-            current_epoch: CURRENT_EPOCH,
-            last_processed: time(),
-        })
-    }
+    Ok(EpochInfo {
+        current_epoch: current_epoch(),
+        last_processed: time(),
+    })
 }
 
 // ============ PRIVACY LAYER ============
 
 #[query]
 fn get_engine_public_key() -> Result<String, String> {
-    unsafe { Ok(ENGINE_PUBLIC_KEY.clone()) }
+    Ok(scalars().engine_public_key)
+}
+
+// The chain/domain ID clients must fold into commitment hashes (see
+// `compute_commitment_hash`) so their commitments are scoped to this
+// deployment.
+#[query]
+fn get_chain_id() -> Result<u64, String> {
+    Ok(scalars().chain_id)
 }
 
 #[update]
 fn verify_commitment(commitment: String, encrypted_order: EncryptedOrder) -> Result<bool, String> {
-    // In production, this would verify the commitment matches the encrypted order
-    // For now, return true if commitment exists
-    unsafe {
-        if let Some(commitments) = &COMMITMENTS {
-            Ok(commitments.contains_key(&commitment))
-        } else {
-            Ok(false)
-        }
+    let exists = COMMITMENTS.with(|c| c.borrow().contains_key(&commitment));
+    if !exists {
+        return Ok(false);
     }
+
+    let expected_commitment = compute_commitment_hash(
+        encrypted_order.chain_id,
+        &encrypted_order.token_in,
+        &encrypted_order.token_out,
+        &encrypted_order.amount_in,
+        &encrypted_order.amount_out,
+        encrypted_order.is_buy,
+        encrypted_order.nonce,
+        &encrypted_order.trader,
+        &encrypted_order.blinding,
+    );
+
+    Ok(expected_commitment == commitment)
 }
 
 // ============ ADMINISTRATIVE FUNCTIONS ============
 
 #[update]
 fn set_trading_pair(pair: String, config: TradingPair) -> Result<bool, String> {
-    unsafe {
-        if TRADING_PAIRS.is_none() {
-            TRADING_PAIRS = Some(HashMap::new());
-        }
-        
-        let pairs = TRADING_PAIRS.as_mut().unwrap();
-        pairs.insert(pair.clone(), config);
-        
-        ic_cdk::println!("Trading pair updated: {}", pair);
-        Ok(true)
+    TRADING_PAIRS.with(|pairs| pairs.borrow_mut().insert(pair.clone(), config));
+
+    ic_cdk::println!("Trading pair updated: {}", pair);
+    Ok(true)
+}
+
+#[update]
+fn set_chain_id(chain_id: u64) -> Result<bool, String> {
+    require_admin()?;
+    update_scalars(|s| s.chain_id = chain_id);
+    ic_cdk::println!("Chain ID set to {}", chain_id);
+    Ok(true)
+}
+
+#[update]
+fn set_reveal_window(epochs: u64) -> Result<bool, String> {
+    update_scalars(|s| s.reveal_window_epochs = epochs);
+    ic_cdk::println!("Reveal window set to {} epochs", epochs);
+    Ok(true)
+}
+
+// Opts into the next on-disk schema version. Gated behind pausing trading
+// first since it rewrites every stored order's version tag; leaves
+// `storage_version` at 1 (and new writes untouched) until explicitly called,
+// so an operator can always roll back to a build that only understands the
+// old layout.
+#[update]
+fn migrate_schema(target_version: u32) -> Result<u32, String> {
+    require_admin()?;
+
+    let current = scalars();
+    if !current.is_paused {
+        return Err("Pause trading before migrating schema".to_string());
+    }
+    if target_version < current.storage_version {
+        return Err("Cannot downgrade schema version".to_string());
+    }
+    if target_version > CURRENT_ORDER_VERSION {
+        return Err("Unknown target schema version".to_string());
+    }
+
+    update_scalars(|s| {
+        s.new_schema_enabled = target_version > 1;
+        s.storage_version = target_version;
+    });
+
+    let order_ids: Vec<String> = ORDERS.with(|o| o.borrow().iter().map(|(id, _)| id).collect());
+    for order_id in order_ids {
+        ORDERS.with(|o| {
+            let mut orders = o.borrow_mut();
+            if let Some(mut order) = orders.get(&order_id) {
+                if target_version >= 2 && order.version < 2 {
+                    let amount_in: f64 = order.amount_in.parse().unwrap_or(0.0);
+                    let filled: f64 = order.filled_amount.parse().unwrap_or(0.0);
+                    order.remaining_amount = (amount_in - filled).max(0.0).to_string();
+                }
+                order.version = target_version;
+                orders.insert(order_id.clone(), order);
+            }
+        });
     }
+
+    ic_cdk::println!("Schema migrated to version {}", target_version);
+    Ok(target_version)
 }
 
 #[update]
 fn pause_trading() -> Result<bool, String> {
-    unsafe {
-        IS_PAUSED = true;
-        ic_cdk::println!("Trading paused");
-        Ok(true)
-    }
+    require_admin()?;
+    update_scalars(|s| s.is_paused = true);
+    ic_cdk::println!("Trading paused");
+    Ok(true)
 }
 
 #[update]
 fn resume_trading() -> Result<bool, String> {
-    unsafe {
-        IS_PAUSED = false;
-        ic_cdk::println!("Trading resumed");
-        Ok(true)
-    }
+    require_admin()?;
+    update_scalars(|s| s.is_paused = false);
+    ic_cdk::println!("Trading resumed");
+    Ok(true)
 }
 
 // ============ HELPER TYPES ============
@@ -598,10 +1416,297 @@ pub struct SystemStatus {
     pub is_paused: bool,
     pub total_orders: u64,
     pub active_channels: u64,
+    pub storage_version: u32,
 }
 
 #[derive(CandidType, Deserialize, Clone, SerdeSerialize, SerdeDeserialize)]
 pub struct EpochInfo {
     pub current_epoch: u64,
     pub last_processed: u64,
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_pair() -> TradingPair {
+        TradingPair {
+            token_in: "ETH".to_string(),
+            token_out: "USDC".to_string(),
+            min_order_size: "0.001".to_string(),
+            max_order_size: "100".to_string(),
+            trading_fee: 50,
+            is_active: true,
+        }
+    }
+
+    fn sample_order(id: &str, token_in: &str, token_out: &str, amount_in: &str, amount_out: &str, is_buy: bool) -> Order {
+        Order {
+            id: id.to_string(),
+            trader: "trader".to_string(),
+            token_in: token_in.to_string(),
+            token_out: token_out.to_string(),
+            amount_in: amount_in.to_string(),
+            amount_out: amount_out.to_string(),
+            is_buy,
+            nonce: 0,
+            timestamp: 0,
+            commitment: "0xabc".to_string(),
+            is_revealed: true,
+            is_executed: false,
+            is_cancelled: false,
+            remaining_amount: amount_in.to_string(),
+            filled_amount: "0".to_string(),
+            chain_id: 0,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn buy_and_sell_prices_agree_in_the_same_unit() {
+        let pair = sample_pair();
+        // Buy: gives 2000 USDC, wants 1 ETH -> willing to pay up to 2000 USDC/ETH.
+        let buy = sample_order("buy-1", "USDC", "ETH", "2000", "1", true);
+        // Sell: gives 1 ETH, wants 1900 USDC -> asking 1900 USDC/ETH.
+        let sell = sample_order("sell-1", "ETH", "USDC", "1", "1900", false);
+
+        let buy_price = order_price(&buy, &pair);
+        let sell_price = order_price(&sell, &pair);
+
+        assert!((buy_price - 2000.0).abs() < 1e-9);
+        assert!((sell_price - 1900.0).abs() < 1e-9);
+        assert!(sell_price <= buy_price, "a 1900 ask should cross a 2000 bid");
+    }
+
+    #[test]
+    fn apply_fill_updates_remaining_and_filled_amounts() {
+        let order = sample_order("order-1", "USDC", "ETH", "2000", "1", true);
+        ORDERS.with(|o| o.borrow_mut().insert(order.id.clone(), order));
+
+        apply_fill("order-1", 500.0);
+
+        let updated = ORDERS.with(|o| o.borrow().get("order-1")).unwrap();
+        assert_eq!(updated.remaining_amount, "1500");
+        assert_eq!(updated.filled_amount, "500");
+        assert!(!updated.is_executed);
+
+        apply_fill("order-1", 1500.0);
+        let updated = ORDERS.with(|o| o.borrow().get("order-1")).unwrap();
+        assert_eq!(updated.remaining_amount, "0");
+        assert!(updated.is_executed);
+
+        ORDERS.with(|o| o.borrow_mut().remove("order-1"));
+    }
+
+    #[test]
+    fn rollback_restores_remaining_and_filled_amounts() {
+        // rollback_match is admin-gated; authorize this test's caller first.
+        update_scalars(|s| s.admin = Some(ic_cdk::caller()));
+
+        let mut buy = sample_order("buy-1", "USDC", "ETH", "500", "1", true);
+        buy.remaining_amount = "0".to_string();
+        buy.filled_amount = "500".to_string();
+        buy.is_executed = true;
+        let mut sell = sample_order("sell-1", "ETH", "USDC", "500", "1900", false);
+        sell.remaining_amount = "0".to_string();
+        sell.filled_amount = "500".to_string();
+        sell.is_executed = true;
+        ORDERS.with(|o| {
+            let mut orders = o.borrow_mut();
+            orders.insert(buy.id.clone(), buy);
+            orders.insert(sell.id.clone(), sell);
+        });
+        MATCHES.with(|m| {
+            m.borrow_mut().insert(
+                "match-1".to_string(),
+                Match {
+                    id: "match-1".to_string(),
+                    buy_order: "buy-1".to_string(),
+                    sell_order: "sell-1".to_string(),
+                    price: "1900".to_string(),
+                    amount: "500".to_string(),
+                    fee_amount: "10".to_string(),
+                    timestamp: 0,
+                    executed_at: 0,
+                    version: CURRENT_MATCH_VERSION,
+                },
+            )
+        });
+
+        rollback_match("match-1".to_string()).unwrap();
+
+        let buy = ORDERS.with(|o| o.borrow().get("buy-1")).unwrap();
+        assert_eq!(buy.remaining_amount, "500");
+        assert_eq!(buy.filled_amount, "0");
+        assert!(!buy.is_executed);
+
+        let sell = ORDERS.with(|o| o.borrow().get("sell-1")).unwrap();
+        assert_eq!(sell.remaining_amount, "500");
+        assert_eq!(sell.filled_amount, "0");
+        assert!(!sell.is_executed);
+
+        ORDERS.with(|o| {
+            let mut orders = o.borrow_mut();
+            orders.remove("buy-1");
+            orders.remove("sell-1");
+        });
+        update_scalars(|s| s.admin = None);
+    }
+
+    fn sample_encrypted_order(
+        trader: &str,
+        nonce: u64,
+        token_in: &str,
+        token_out: &str,
+        amount_in: &str,
+        amount_out: &str,
+        chain_id: u64,
+    ) -> EncryptedOrder {
+        let blinding = "blind";
+        let commitment = compute_commitment_hash(
+            chain_id, token_in, token_out, amount_in, amount_out, true, nonce, trader, blinding,
+        );
+        EncryptedOrder {
+            encrypted_data: "".to_string(),
+            commitment,
+            timestamp: 0,
+            nonce,
+            trader: trader.to_string(),
+            token_in: token_in.to_string(),
+            token_out: token_out.to_string(),
+            amount_in: amount_in.to_string(),
+            amount_out: amount_out.to_string(),
+            is_buy: true,
+            blinding: blinding.to_string(),
+            chain_id,
+        }
+    }
+
+    #[test]
+    fn run_matching_engine_crosses_and_fills_both_orders() {
+        TRADING_PAIRS.with(|pairs| pairs.borrow_mut().insert("ETH/USDC".to_string(), sample_pair()));
+
+        let buy = sample_order("buy-1", "USDC", "ETH", "2000", "1", true);
+        let sell = sample_order("sell-1", "ETH", "USDC", "1", "1900", false);
+        ORDERS.with(|o| {
+            let mut orders = o.borrow_mut();
+            orders.insert(buy.id.clone(), buy);
+            orders.insert(sell.id.clone(), sell);
+        });
+
+        run_matching_engine("buy-1");
+
+        let matches: Vec<Match> = MATCHES.with(|m| m.borrow().iter().map(|(_, v)| v).collect());
+        assert_eq!(matches.len(), 1, "a crossing buy/sell pair should produce exactly one match");
+        let the_match = &matches[0];
+        assert_eq!(the_match.buy_order, "buy-1");
+        assert_eq!(the_match.sell_order, "sell-1");
+
+        let buy = ORDERS.with(|o| o.borrow().get("buy-1")).unwrap();
+        let sell = ORDERS.with(|o| o.borrow().get("sell-1")).unwrap();
+        assert!(buy.is_executed, "buy should be fully filled by the smaller sell");
+        assert!(sell.is_executed, "sell should be fully filled");
+    }
+
+    #[test]
+    fn run_matching_engine_does_not_cross_non_overlapping_prices() {
+        TRADING_PAIRS.with(|pairs| pairs.borrow_mut().insert("ETH/USDC".to_string(), sample_pair()));
+
+        // Buy only willing to pay 1000 USDC/ETH; sell asking 1900 USDC/ETH -- no cross.
+        let buy = sample_order("buy-2", "USDC", "ETH", "1000", "1", true);
+        let sell = sample_order("sell-2", "ETH", "USDC", "1", "1900", false);
+        ORDERS.with(|o| {
+            let mut orders = o.borrow_mut();
+            orders.insert(buy.id.clone(), buy);
+            orders.insert(sell.id.clone(), sell);
+        });
+
+        run_matching_engine("buy-2");
+
+        let matches: Vec<Match> = MATCHES.with(|m| m.borrow().iter().map(|(_, v)| v).collect());
+        assert!(matches.is_empty(), "non-crossing orders must not produce a match");
+
+        let buy = ORDERS.with(|o| o.borrow().get("buy-2")).unwrap();
+        assert!(!buy.is_executed);
+    }
+
+    #[test]
+    fn sanitize_order_rejects_duplicate_commitment() {
+        TRADING_PAIRS.with(|pairs| pairs.borrow_mut().insert("ETH/USDC".to_string(), sample_pair()));
+        let order = sample_encrypted_order("trader-a", 1, "USDC", "ETH", "2000", "1", 0);
+        REVEALED_COMMITMENTS.with(|r| r.borrow_mut().insert(order.commitment.clone(), 1u8));
+
+        assert_eq!(sanitize_order(&order), Err(RevealError::DuplicateOrder));
+    }
+
+    #[test]
+    fn sanitize_order_rejects_nonce_replay() {
+        TRADING_PAIRS.with(|pairs| pairs.borrow_mut().insert("ETH/USDC".to_string(), sample_pair()));
+        TRADER_NONCES.with(|n| n.borrow_mut().insert("trader-b".to_string(), 5));
+
+        let order = sample_encrypted_order("trader-b", 5, "USDC", "ETH", "2000", "1", 0);
+        assert_eq!(sanitize_order(&order), Err(RevealError::NonceReplay));
+
+        let stale = sample_encrypted_order("trader-b", 3, "USDC", "ETH", "2000", "1", 0);
+        assert_eq!(sanitize_order(&stale), Err(RevealError::NonceReplay));
+    }
+
+    #[test]
+    fn sanitize_order_rejects_inactive_and_out_of_bounds() {
+        let mut inactive_pair = sample_pair();
+        inactive_pair.is_active = false;
+        TRADING_PAIRS.with(|pairs| pairs.borrow_mut().insert("ETH/USDC".to_string(), inactive_pair));
+
+        let order = sample_encrypted_order("trader-c", 1, "USDC", "ETH", "2000", "1", 0);
+        assert_eq!(sanitize_order(&order), Err(RevealError::InactiveTradingPair));
+
+        TRADING_PAIRS.with(|pairs| pairs.borrow_mut().insert("ETH/USDC".to_string(), sample_pair()));
+        let too_small = sample_encrypted_order("trader-d", 1, "USDC", "ETH", "0.0000001", "1", 0);
+        assert_eq!(sanitize_order(&too_small), Err(RevealError::AmountOutOfBounds));
+
+        let not_a_number = sample_encrypted_order("trader-e", 1, "USDC", "ETH", "not-a-number", "1", 0);
+        assert_eq!(sanitize_order(&not_a_number), Err(RevealError::InvalidAmount));
+
+        let valid = sample_encrypted_order("trader-f", 1, "USDC", "ETH", "50", "1", 0);
+        assert_eq!(sanitize_order(&valid), Ok(()));
+    }
+
+    #[test]
+    fn compute_commitment_hash_is_deterministic_and_field_sensitive() {
+        let a = compute_commitment_hash(0, "USDC", "ETH", "2000", "1", true, 1, "trader", "blind");
+        let b = compute_commitment_hash(0, "USDC", "ETH", "2000", "1", true, 1, "trader", "blind");
+        assert_eq!(a, b, "hashing the same fields twice must agree");
+
+        let different_chain = compute_commitment_hash(1, "USDC", "ETH", "2000", "1", true, 1, "trader", "blind");
+        assert_ne!(a, different_chain, "chain_id must be bound into the commitment");
+    }
+
+    #[test]
+    fn recover_eth_address_matches_the_signer() {
+        use k256::ecdsa::SigningKey;
+
+        let signing_key = SigningKey::from_bytes(&[7u8; 32].into()).unwrap();
+        let verifying_key = signing_key.verifying_key();
+        let encoded_point = verifying_key.to_encoded_point(false);
+        let expected_address = format!(
+            "0x{}",
+            hex::encode(&Keccak256::digest(&encoded_point.as_bytes()[1..])[12..])
+        );
+
+        let message_hash = state_update_hash(0, "channel-1", "100", 1);
+        let (signature, recovery_id) = signing_key.sign_prehash_recoverable(&message_hash).unwrap();
+        let mut sig_bytes = signature.to_bytes().to_vec();
+        sig_bytes.push(recovery_id.to_byte());
+        let sig_hex = format!("0x{}", hex::encode(&sig_bytes));
+
+        let recovered = recover_eth_address(&message_hash, &sig_hex).unwrap();
+        assert_eq!(recovered, expected_address);
+    }
+
+    #[test]
+    fn recover_eth_address_rejects_malformed_signature() {
+        let message_hash = state_update_hash(0, "channel-1", "100", 1);
+        assert!(recover_eth_address(&message_hash, "0xnot-hex").is_err());
+        assert!(recover_eth_address(&message_hash, "0x00").is_err());
+    }
+}